@@ -10,9 +10,11 @@ extern crate alloc;
 
 use super::gdt::load_tss;
 use super::tss::{X86Tss, IST_DF};
+use crate::cpu::msr::{read_msr, write_msr};
 use crate::cpu::tss::TSS_LIMIT;
 use crate::mm::{SVSM_PERCPU_BASE, SVSM_STACKS_INIT_TASK, SVSM_PERCPU_VMSA_BASE,
-    SVSM_STACK_IST_DF_BASE, SVSM_PERCPU_CAA_BASE, virt_to_phys, phys_to_virt};
+    SVSM_STACK_IST_DF_BASE, SVSM_PERCPU_CAA_BASE, SVSM_PERCPU_PROFILE_BASE,
+    virt_to_phys, phys_to_virt};
 use crate::mm::alloc::{allocate_page, allocate_zeroed_page};
 use crate::mm::stack::{allocate_stack_addr, stack_base_pointer};
 use crate::mm::pagetable::{PageTable, PageTableRef, get_init_pgtable_locked};
@@ -20,13 +22,168 @@ use crate::sev::ghcb::GHCB;
 use crate::sev::utils::RMPFlags;
 use crate::sev::vmsa::{allocate_new_vmsa, free_vmsa, VMSASegment, VMSA};
 use crate::types::{PhysAddr, VirtAddr};
-use crate::types::{SVSM_TR_FLAGS, SVSM_TSS};
+use crate::types::{SVSM_TR_FLAGS, SVSM_TSS, SVSM_CS, SVSM_USER_CS};
 use crate::cpu::vmsa::init_guest_vmsa;
 use crate::utils::{page_align, page_offset};
 use crate::locking::{SpinLock, LockGuard};
+use crate::task::tasks::{Task, TaskRuntime};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
 use alloc::vec::Vec;
 use core::ptr;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Number of samples a per-CPU profiling ring buffer holds before the
+/// oldest entry is overwritten. Sized, together with the 16-byte cursor
+/// header in [`ProfileRingBuffer`], to fit exactly within the single 4K
+/// page [`PerCpu::map_profile_buffer`] maps for the consumer.
+const PROFILE_SAMPLE_CAPACITY: usize = 170;
+
+/// Default number of scheduler-out events between captured samples.
+const PROFILE_DEFAULT_INTERVAL: u64 = 100;
+
+/// One scheduler-event captured by the statistical profiler.
+///
+/// Kept `repr(C)` because this struct is read directly out of the shared
+/// page mapped into the consuming guest's address space. Fields are
+/// ordered largest-first so the struct packs to 24 bytes with no padding;
+/// `task_id`/`seq` trailing at the end would otherwise each pad out to 8
+/// bytes next to the `u64`s.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProfileSample {
+    pub rip: u64,
+    pub runtime_delta: u64,
+    pub task_id: u32,
+    pub seq: u32,
+}
+
+/// Lossy, fixed-size ring buffer of [`ProfileSample`]s for one CPU.
+///
+/// Samples are produced far faster than any consumer can be expected to
+/// drain them, so rather than blocking the scheduler the buffer overwrites
+/// its oldest entry once full and counts the loss in `dropped`. This
+/// mirrors the "summarize into a lossy structure, ship it out" approach
+/// used by statistical profilers such as Chopstix.
+///
+/// `PROFILE_SAMPLE_CAPACITY * size_of::<ProfileSample>() + 16` (this
+/// struct's own cursor fields) must stay within 4096 bytes: the whole
+/// buffer is mapped to the consumer as a single page by
+/// [`PerCpu::map_profile_buffer`], so the cursors it needs to drain the
+/// buffer have to live inside that same page.
+#[repr(C)]
+pub struct ProfileRingBuffer {
+    samples: [ProfileSample; PROFILE_SAMPLE_CAPACITY],
+    write_idx: u32,
+    read_idx: u32,
+    dropped: u64,
+}
+
+impl ProfileRingBuffer {
+    const fn new() -> Self {
+        ProfileRingBuffer {
+            samples: [ProfileSample {
+                rip: 0,
+                runtime_delta: 0,
+                task_id: 0,
+                seq: 0,
+            }; PROFILE_SAMPLE_CAPACITY],
+            write_idx: 0,
+            read_idx: 0,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, task_id: u32, rip: u64, runtime_delta: u64) {
+        let idx = (self.write_idx as usize) % PROFILE_SAMPLE_CAPACITY;
+        let next = self.write_idx.wrapping_add(1);
+
+        // The buffer is full once the write cursor has lapped the read
+        // cursor; drop the oldest sample rather than block the scheduler.
+        if (next.wrapping_sub(self.read_idx) as usize) > PROFILE_SAMPLE_CAPACITY {
+            self.read_idx = self.read_idx.wrapping_add(1);
+            self.dropped += 1;
+        }
+
+        self.samples[idx] = ProfileSample {
+            task_id,
+            rip,
+            runtime_delta,
+            seq: self.write_idx,
+        };
+        self.write_idx = next;
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+// SYSCALL/SYSRET MSRs (Intel SDM / AMD APM, architecturally fixed numbers).
+const MSR_EFER: u32 = 0xc000_0080;
+const MSR_STAR: u32 = 0xc000_0081;
+const MSR_LSTAR: u32 = 0xc000_0082;
+const MSR_FMASK: u32 = 0xc000_0084;
+const EFER_SCE: u64 = 1 << 0;
+
+extern "C" {
+    // Implemented in assembly alongside the context-switch trampoline:
+    // swaps to the task's kernel stack (via the TSS), saves the user
+    // context, calls into `handle_syscall`, then `sysret`s back to CPL 3.
+    fn syscall_entry();
+}
+
+/// Minimum difference in runnable-task count between the busiest and
+/// least-loaded CPU before a task is worth migrating. Keeps tasks from
+/// ping-ponging back and forth between two CPUs whose load is already
+/// close.
+const LOAD_BALANCE_THRESHOLD: u64 = 2;
+
+/// Runnable tasks owned by one CPU, plus enough aggregate state for the
+/// load balancer to judge how busy this CPU is.
+struct RunQueue {
+    tasks: VecDeque<Box<Task>>,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        RunQueue {
+            tasks: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, task: Box<Task>) {
+        self.tasks.push_back(task);
+    }
+
+    /// Number of runnable tasks on this queue, used by the load balancer
+    /// as its load measure. `TaskRuntime::value()` is a monotonically
+    /// growing quantity (deadline, vruntime, ...) rather than a
+    /// point-in-time load, so summing it would reflect task age/uptime
+    /// instead of how busy the CPU currently is.
+    fn len(&self) -> u64 {
+        self.tasks.len() as u64
+    }
+
+    /// Smallest `TaskRuntime::value()` among the tasks currently queued
+    /// here (not counting whichever task is presently running), or `None`
+    /// if nothing is queued.
+    fn min_vruntime(&self) -> Option<u64> {
+        self.tasks.iter().map(|t| t.runtime.value()).min()
+    }
+
+    /// Removes and returns the task with the greatest `TaskRuntime::value()`
+    /// on this queue, so it can be migrated to a less loaded CPU.
+    fn take_heaviest(&mut self) -> Option<Box<Task>> {
+        let idx = self
+            .tasks
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, t)| t.runtime.value())
+            .map(|(i, _)| i)?;
+        self.tasks.remove(idx)
+    }
+}
 
 struct PerCpuInfo {
     apic_id: u32,
@@ -84,6 +241,16 @@ pub struct PerCpu {
     guest_vmsa: SpinLock::<Option<VmsaRef>>,
     caa_addr: Option<VirtAddr>,
     reset_ip: u64,
+    /// Page-aligned allocation backing this CPU's `ProfileRingBuffer`,
+    /// kept as its own dedicated page (rather than a field embedded in
+    /// `PerCpu`) so it can be mapped whole and unaliased into the
+    /// consumer's address space.
+    profile_page: Option<VirtAddr>,
+    profile_lock: SpinLock<()>,
+    profile_addr: Option<VirtAddr>,
+    profile_interval: u64,
+    min_vruntime: AtomicU64,
+    run_queue: SpinLock<RunQueue>,
 }
 
 impl PerCpu {
@@ -100,6 +267,12 @@ impl PerCpu {
             guest_vmsa: SpinLock::new(None),
             caa_addr: None,
             reset_ip: 0xffff_fff0u64,
+            profile_page: None,
+            profile_lock: SpinLock::new(()),
+            profile_addr: None,
+            profile_interval: PROFILE_DEFAULT_INTERVAL,
+            min_vruntime: AtomicU64::new(0),
+            run_queue: SpinLock::new(RunQueue::new()),
         }
     }
 
@@ -158,6 +331,18 @@ impl PerCpu {
         Ok(())
     }
 
+    /// Allocates the dedicated, page-aligned page backing this CPU's
+    /// profiling ring buffer, so it can later be mapped as a whole,
+    /// unaliased page into a consumer's address space.
+    fn allocate_profile_buffer(&mut self) -> Result<(), ()> {
+        let vaddr = allocate_zeroed_page()?;
+        unsafe {
+            (vaddr as *mut ProfileRingBuffer).write(ProfileRingBuffer::new());
+        }
+        self.profile_page = Some(vaddr);
+        Ok(())
+    }
+
     pub fn get_pgtable(&self) -> LockGuard<PageTableRef> {
         self.pgtbl.lock()
     }
@@ -204,6 +389,9 @@ impl PerCpu {
         // Allocate IST stacks
         self.allocate_ist_stacks()?;
 
+        // Allocate the profiling ring buffer's backing page
+        self.allocate_profile_buffer()?;
+
         // Setup TSS
         self.setup_tss();
 
@@ -212,7 +400,31 @@ impl PerCpu {
 
     // Setup code which needs to run on the target CPU
     pub fn setup_on_cpu(&self) -> Result<(), ()> {
-        self.register_ghcb()
+        self.register_ghcb()?;
+        self.setup_syscall();
+        Ok(())
+    }
+
+    /// Programs the SYSCALL/SYSRET MSRs so user-mode (CPL 3) tasks can
+    /// enter the SVSM kernel without going through the full interrupt-gate
+    /// path.
+    fn setup_syscall(&self) {
+        // STAR: packs the kernel and user CS:SS pairs SYSCALL/SYSRET use.
+        write_msr(MSR_STAR, ((SVSM_USER_CS as u64) << 48) | ((SVSM_CS as u64) << 32));
+        // LSTAR: entry point executed on SYSCALL.
+        write_msr(MSR_LSTAR, syscall_entry as *const () as u64);
+        // FMASK: RFLAGS bits cleared on entry (interrupts off, direction flag clear).
+        write_msr(MSR_FMASK, 0x700);
+
+        let efer = read_msr(MSR_EFER);
+        write_msr(MSR_EFER, efer | EFER_SCE);
+    }
+
+    /// Points the TSS's ring-0 stack pointer at `top`, the kernel stack the
+    /// `syscall_entry`/interrupt-gate path should switch to when entering
+    /// from CPL 3. Called by the scheduler when switching to a user task.
+    pub fn set_user_kernel_stack(&mut self, top: VirtAddr) {
+        self.tss.rsp0 = top;
     }
 
     pub fn load_pgtable(&mut self) {
@@ -371,6 +583,156 @@ impl PerCpu {
 
         Ok(())
     }
+
+    pub fn set_profile_interval(&mut self, ticks: u64) {
+        self.profile_interval = ticks;
+    }
+
+    pub fn profile_interval(&self) -> u64 {
+        self.profile_interval
+    }
+
+    /// Records one scheduler-out sample into this CPU's profiling ring
+    /// buffer. Called once the configured sample interval has elapsed at
+    /// the `TaskRuntime::schedule_out` call sites in `task::tasks`.
+    pub fn record_profile_sample(&self, task_id: u32, rip: u64, runtime_delta: u64) {
+        let _guard = self.profile_lock.lock();
+        let page = self.profile_page.expect("profile buffer not allocated");
+        unsafe { (&mut *(page as *mut ProfileRingBuffer)).push(task_id, rip, runtime_delta) };
+    }
+
+    pub fn profile_dropped(&self) -> u64 {
+        let _guard = self.profile_lock.lock();
+        let page = self.profile_page.expect("profile buffer not allocated");
+        unsafe { (&*(page as *const ProfileRingBuffer)).dropped() }
+    }
+
+    /// Maps this CPU's profiling ring buffer into its own page table so a
+    /// privileged guest can drain samples without stopping the scheduler,
+    /// reusing the same `map_4k` machinery as [`PerCpu::map_caa_phys`].
+    ///
+    /// The buffer lives on its own dedicated page (allocated in
+    /// [`PerCpu::allocate_profile_buffer`]) rather than inside `PerCpu`
+    /// itself, so this maps a whole, correctly-aligned page instead of an
+    /// unaligned interior pointer.
+    pub fn map_profile_buffer(&mut self) -> Result<(), ()> {
+        let vaddr = self.profile_page.ok_or(())?;
+        let paddr = virt_to_phys(vaddr);
+        let flags = PageTable::data_flags();
+
+        self.get_pgtable().map_4k(SVSM_PERCPU_PROFILE_BASE, paddr, &flags)?;
+        self.profile_addr = Some(SVSM_PERCPU_PROFILE_BASE);
+
+        Ok(())
+    }
+
+    pub fn profile_addr(&self) -> Option<VirtAddr> {
+        self.profile_addr
+    }
+
+    /// Smallest virtual runtime among this CPU's runnable tasks, as of the
+    /// last update. Monotonically non-decreasing, so new or woken tasks
+    /// can be seeded from it without ever jumping backwards.
+    pub fn min_vruntime(&self) -> u64 {
+        self.min_vruntime.load(Ordering::Relaxed)
+    }
+
+    /// Recomputes `min_vruntime` from `current` (the vruntime of the task
+    /// that was just descheduled) and the vruntime of the leftmost task
+    /// still queued, following the same rule as Linux CFS:
+    /// `min_vruntime = max(min_vruntime, min(current, queue_min))`. Taking
+    /// the `min()` of the two candidates is what makes this track the
+    /// smallest *runnable* vruntime instead of just the descheduled task's
+    /// own value; the outer `max()` against the previous value is what
+    /// keeps it from ever moving backwards. Called from
+    /// `Task::schedule_out`.
+    pub fn update_min_vruntime(&self, current: u64) {
+        let candidate = match self.run_queue.lock().min_vruntime() {
+            Some(queue_min) => current.min(queue_min),
+            None => current,
+        };
+        self.min_vruntime.fetch_max(candidate, Ordering::Relaxed);
+    }
+
+    /// Enqueues `task` onto this CPU's run queue. Used both for freshly
+    /// created tasks and for tasks migrated in by the load balancer.
+    pub fn enqueue_task(&self, task: Box<Task>) {
+        self.run_queue.lock().push(task);
+    }
+
+    /// Number of runnable tasks on this CPU's run queue, used to pick a
+    /// home for new tasks and to judge load balance.
+    pub fn run_queue_load(&self) -> u64 {
+        self.run_queue.lock().len()
+    }
+
+    /// Re-homes a task that is not currently running onto this CPU: its
+    /// page-table and per-CPU entries follow it automatically the next
+    /// time it runs here, per the existing note in
+    /// `Task::allocate_page_table` ("when the pagetable is scheduled to a
+    /// CPU, the per CPU entry will also be added"), so migration is just
+    /// populating our own page table against the task's and enqueueing it.
+    fn migrate_task(&self, task: Box<Task>) {
+        {
+            let mut pgtable = task.page_table.lock();
+            self.populate_page_table(&mut pgtable);
+        }
+
+        self.enqueue_task(task);
+    }
+}
+
+/// Returns the online CPU with the fewest runnable tasks on its run queue,
+/// used to pick a home for a newly created or woken task.
+pub fn least_loaded_cpu() -> Option<&'static PerCpu> {
+    PERCPU_AREAS
+        .lock()
+        .iter()
+        .filter_map(|info| unsafe { (info.addr as *const PerCpu).as_ref() })
+        .filter(|cpu| cpu.is_online())
+        .min_by_key(|cpu| cpu.run_queue_load())
+}
+
+/// Periodic load-balancing pass, intended to be driven from the timer
+/// interrupt path. Compares runnable-task counts across all online CPUs
+/// and, if the busiest and least-loaded queues differ by at least
+/// [`LOAD_BALANCE_THRESHOLD`], migrates the busiest queue's heaviest
+/// (by `TaskRuntime::value()`) task over to the least-loaded one.
+pub fn balance_load() {
+    let areas = PERCPU_AREAS.lock();
+
+    let mut busiest: Option<(&PerCpu, u64)> = None;
+    let mut idlest: Option<(&PerCpu, u64)> = None;
+
+    for info in areas.iter() {
+        let cpu = unsafe { (info.addr as *const PerCpu).as_ref().unwrap() };
+        if !cpu.is_online() {
+            continue;
+        }
+
+        let load = cpu.run_queue_load();
+        if busiest.map_or(true, |(_, l)| load > l) {
+            busiest = Some((cpu, load));
+        }
+        if idlest.map_or(true, |(_, l)| load < l) {
+            idlest = Some((cpu, load));
+        }
+    }
+
+    let (Some((from, from_load)), Some((to, to_load))) = (busiest, idlest) else {
+        return;
+    };
+
+    if from.get_apic_id() == to.get_apic_id() {
+        return;
+    }
+    if from_load.saturating_sub(to_load) < LOAD_BALANCE_THRESHOLD {
+        return;
+    }
+
+    if let Some(task) = from.run_queue.lock().take_heaviest() {
+        to.migrate_task(task);
+    }
 }
 
 unsafe impl Sync for PerCpu {}
@@ -400,3 +762,15 @@ pub fn percpu(apic_id: u32) -> Option<&'static PerCpu> {
     None
 }
 
+/// Called by `syscall_entry` once it has switched onto the current task's
+/// kernel stack. Dispatches into a small syscall table; unrecognized
+/// syscall numbers return `-1`.
+#[no_mangle]
+extern "C" fn handle_syscall(nr: u64, arg0: u64, _arg1: u64, _arg2: u64) -> i64 {
+    match nr {
+        // SYS_NOP: echoes arg0 back, useful for probing the entry path.
+        0 => arg0 as i64,
+        _ => -1,
+    }
+}
+
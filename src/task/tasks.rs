@@ -14,14 +14,20 @@ use core::sync::atomic::{AtomicU32, Ordering};
 
 use crate::address::{Address, VirtAddr};
 use crate::cpu::msr::{rdtsc, read_flags};
-use crate::cpu::percpu::{this_cpu, this_cpu_mut};
+use crate::cpu::percpu::{least_loaded_cpu, this_cpu, this_cpu_mut};
 use crate::cpu::X86GeneralRegs;
 use crate::error::SvsmError;
 use crate::locking::SpinLock;
-use crate::mm::pagetable::{get_init_pgtable_locked, PTEntryFlags, PageTableRef};
+use crate::mm::alloc::allocate_zeroed_page;
+use crate::mm::pagetable::{get_init_pgtable_locked, PTEntryFlags, PageTable, PageTableRef};
 use crate::mm::stack::StackBounds;
 use crate::mm::vm::{Mapping, VMKernelStack, VMR};
-use crate::mm::{SVSM_PERTASK_BASE, SVSM_PERTASK_END, SVSM_PERTASK_STACK_BASE};
+use crate::mm::{
+    phys_to_virt, virt_to_phys, PAGE_SIZE, SVSM_PERTASK_BASE, SVSM_PERTASK_END,
+    SVSM_PERTASK_STACK_BASE, SVSM_PERTASK_USER_STACK_BASE,
+};
+use crate::utils::page_align;
+use crate::types::{SVSM_USER_CS, SVSM_USER_DS};
 
 use super::schedule::{current_task_terminated, schedule};
 
@@ -50,6 +56,11 @@ impl From<TaskError> for SvsmError {
 
 pub const TASK_FLAG_SHARE_PT: u16 = 0x01;
 
+/// Small head start subtracted from `min_vruntime` when seeding a new or
+/// woken task's virtual runtime, so it gets to run promptly without being
+/// able to monopolize the CPU ahead of tasks that are already runnable.
+const VRUNTIME_NEW_TASK_GRACE: u64 = 1_000_000;
+
 #[derive(Debug, Default)]
 struct TaskIDAllocator {
     next_id: AtomicU32,
@@ -95,6 +106,15 @@ pub trait TaskRuntime {
     /// Returns a value that represents the amount of CPU the task
     /// has been allocated
     fn value(&self) -> u64;
+
+    /// Returns whether the task is eligible to be selected by the
+    /// scheduler at the given time. Most policies are always eligible;
+    /// budget-limited policies such as [`DeadlineRuntime`] use this to
+    /// mark a runnable task as temporarily throttled without terminating
+    /// it.
+    fn is_eligible(&self, _now: u64) -> bool {
+        true
+    }
 }
 
 /// Tracks task runtime based on the CPU timestamp counter
@@ -146,8 +166,148 @@ impl TaskRuntime for CountRuntime {
     }
 }
 
+/// Deadline-driven runtime policy implementing the Constant Bandwidth
+/// Server (CBS) rule: each task is guaranteed `budget` out of every
+/// `period` TSC ticks. `value()` returns the absolute deadline, so the
+/// existing "lowest value wins" task selection naturally becomes
+/// earliest-deadline-first among `DeadlineRuntime` tasks.
+#[derive(Debug, Clone, Copy)]
+pub struct DeadlineRuntime {
+    /// Replenishment period, in TSC ticks.
+    period: u64,
+    /// Budget granted every period, in TSC ticks.
+    budget: u64,
+    /// Budget remaining in the current period.
+    remaining: u64,
+    /// Absolute deadline, in TSC ticks, for the current period.
+    deadline: u64,
+    /// TSC value at the start of the current scheduling slice.
+    slice_start: u64,
+}
+
+impl DeadlineRuntime {
+    pub fn new(period: u64, budget: u64) -> Self {
+        let now = rdtsc();
+        DeadlineRuntime {
+            period,
+            budget,
+            remaining: budget,
+            deadline: now + period,
+            slice_start: now,
+        }
+    }
+}
+
+impl TaskRuntime for DeadlineRuntime {
+    fn schedule_in(&mut self) {
+        let now = rdtsc();
+
+        // CBS replenishment rule: if the budget is exhausted or the
+        // deadline has already passed, start a fresh period.
+        if self.remaining == 0 || now >= self.deadline {
+            self.remaining = self.budget;
+            self.deadline = now + self.period;
+        }
+
+        self.slice_start = now;
+    }
+
+    fn schedule_out(&mut self) {
+        let delta = rdtsc() - self.slice_start;
+        self.remaining = self.remaining.saturating_sub(delta);
+
+        if self.remaining == 0 {
+            // Budget ran out mid-slice: postpone the deadline to the next
+            // period, but leave `remaining` at 0 rather than replenishing
+            // immediately. The task is throttled (see `is_eligible`) until
+            // real time reaches the postponed deadline, at which point
+            // `schedule_in`'s replenishment rule picks it back up.
+            self.deadline += self.period;
+        }
+    }
+
+    fn set(&mut self, runtime: u64) {
+        self.deadline = runtime;
+    }
+
+    fn value(&self) -> u64 {
+        self.deadline
+    }
+
+    fn is_eligible(&self, now: u64) -> bool {
+        // Throttled exactly when the budget is exhausted and the current
+        // deadline has not yet arrived to trigger a replenishment.
+        self.remaining > 0 || now >= self.deadline
+    }
+}
+
+/// Weight assigned to nice value 0. Matches Linux's `NICE_0_LOAD` so the
+/// weight table below lines up with familiar nice semantics.
+pub const NICE_0_WEIGHT: u64 = 1024;
+
+/// Nice-to-weight table, indexed by `nice + 20`. Each step changes CPU
+/// share by roughly 10%, following the same progression as the Linux CFS
+/// scheduler's `prio_to_weight`.
+const NICE_TO_WEIGHT: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+fn nice_to_weight(nice: i8) -> u64 {
+    let nice = nice.clamp(-20, 19);
+    NICE_TO_WEIGHT[(nice + 20) as usize]
+}
+
+/// CFS-style weighted fair-share runtime: tracks virtual runtime rather
+/// than raw runtime, scaled by a weight derived from the task's nice
+/// value. A task with a smaller weight (lower priority) accrues virtual
+/// time faster and is therefore picked less often by the scheduler's
+/// "lowest value wins" selection.
+#[derive(Debug, Clone, Copy)]
+pub struct WeightedRuntime {
+    vruntime: u64,
+    weight: u64,
+    slice_start: u64,
+}
+
+impl Default for WeightedRuntime {
+    fn default() -> Self {
+        WeightedRuntime {
+            vruntime: 0,
+            weight: NICE_0_WEIGHT,
+            slice_start: 0,
+        }
+    }
+}
+
+impl WeightedRuntime {
+    fn set_weight(&mut self, weight: u64) {
+        self.weight = weight;
+    }
+}
+
+impl TaskRuntime for WeightedRuntime {
+    fn schedule_in(&mut self) {
+        self.slice_start = rdtsc();
+    }
+
+    fn schedule_out(&mut self) {
+        let delta = rdtsc() - self.slice_start;
+        self.vruntime += delta * NICE_0_WEIGHT / self.weight;
+    }
+
+    fn set(&mut self, runtime: u64) {
+        self.vruntime = runtime;
+    }
+
+    fn value(&self) -> u64 {
+        self.vruntime
+    }
+}
+
 // Define which runtime counter to use
-type TaskRuntimeImpl = CountRuntime;
+type TaskRuntimeImpl = WeightedRuntime;
 
 #[repr(C)]
 #[derive(Default, Debug, Clone, Copy)]
@@ -181,6 +341,20 @@ pub struct Task {
 
     /// Amount of CPU resource the task has consumed
     pub runtime: TaskRuntimeImpl,
+
+    /// Nice value this task's `runtime` weight was derived from. Kept on
+    /// the task so [`Task::set_nice`] can recompute the weight without
+    /// needing it passed back in.
+    nice: i8,
+
+    /// Scheduler-out events since the last profiling sample was captured
+    /// for this task.
+    profile_ticks: u64,
+
+    /// TSC value recorded the last time this task was scheduled in, used
+    /// to compute the runtime delta handed to the profiler on the next
+    /// `schedule_out`.
+    last_schedule_in_tsc: u64,
 }
 
 impl fmt::Debug for Task {
@@ -213,6 +387,9 @@ impl Task {
 
         let bounds = raw_bounds.map_at(SVSM_PERTASK_STACK_BASE);
 
+        let mut runtime = TaskRuntimeImpl::default();
+        runtime.set(Self::initial_vruntime());
+
         let task: Box<Task> = Box::new(Task {
             rsp: bounds
                 .top
@@ -225,15 +402,176 @@ impl Task {
             idle_task: false,
             state: TaskState::RUNNING,
             id: TASK_ID_ALLOCATOR.next_id(),
-            runtime: TaskRuntimeImpl::default(),
+            runtime,
+            nice: 0,
+            profile_ticks: 0,
+            last_schedule_in_tsc: 0,
+        });
+        Ok(task)
+    }
+
+    /// Builds a task that runs at CPL 3 (user mode) instead of ring 0.
+    ///
+    /// Mirrors [`Task::create`], but `entry` is a bare user virtual
+    /// address rather than a kernel fn pointer, the task gets its own
+    /// user-accessible stack in addition to the kernel stack used for
+    /// syscall/interrupt entry, and the initial frame is built so the
+    /// first switch into this task `iret`s into user code with user data
+    /// and code selectors rather than resuming a kernel function.
+    pub fn create_user(entry: VirtAddr, flags: u16) -> Result<Box<Task>, SvsmError> {
+        let mut pgtable = if (flags & TASK_FLAG_SHARE_PT) != 0 {
+            this_cpu().get_pgtable().clone_shared()?
+        } else {
+            Self::allocate_page_table()?
+        };
+
+        let mut vm_kernel_range =
+            VMR::new(SVSM_PERTASK_BASE, SVSM_PERTASK_END, PTEntryFlags::empty());
+        vm_kernel_range.initialize()?;
+
+        // Kernel-side stack used while handling syscalls/interrupts raised
+        // from user mode; allocated the same way as a ring-0 task's stack,
+        // but the ring-0 frame written into it is replaced below.
+        let (stack, raw_bounds, _) = Self::allocate_stack(task_exit)?;
+
+        // User-accessible stack the task actually runs on at CPL 3.
+        let user_stack = VMKernelStack::new()?;
+        let user_bounds = user_stack.bounds(VirtAddr::from(0u64));
+        let user_mapping = Arc::new(Mapping::new(user_stack));
+        let user_top = user_bounds.map_at(SVSM_PERTASK_USER_STACK_BASE).top;
+
+        // Overwrite the ring-0 frame allocate_stack() wrote with the iret
+        // frame the low-level switch path expects for a CPL-3 entry. iretq
+        // pops fields low-to-high as RIP, CS, RFLAGS, RSP, SS, and `rsp` is
+        // set below to `top - 5*size_of::<u64>()` -- the lowest address of
+        // this frame -- so the writes here must follow that same order.
+        // The task's own virtual address isn't mapped in the page table
+        // we're currently running on, so write through a temporary per-CPU
+        // mapping of the stack, exactly as allocate_stack() does for the
+        // frame it wrote.
+        {
+            let percpu_mapping = this_cpu_mut().new_mapping(stack.clone())?;
+            let stack_ptr: *mut u64 =
+                (percpu_mapping.virt_addr().bits() + raw_bounds.top.bits()) as *mut u64;
+            unsafe {
+                stack_ptr.offset(-5).write(entry.bits() as u64);
+                stack_ptr.offset(-4).write(SVSM_USER_CS as u64 | 3);
+                stack_ptr.offset(-3).write(read_flags());
+                stack_ptr.offset(-2).write(user_top.bits() as u64);
+                stack_ptr.offset(-1).write(SVSM_USER_DS as u64 | 3);
+            }
+        }
+
+        vm_kernel_range.insert_at(SVSM_PERTASK_STACK_BASE, stack)?;
+        vm_kernel_range.insert_at(SVSM_PERTASK_USER_STACK_BASE, user_mapping)?;
+
+        vm_kernel_range.populate(&mut pgtable);
+
+        let bounds = raw_bounds.map_at(SVSM_PERTASK_STACK_BASE);
+
+        let mut runtime = TaskRuntimeImpl::default();
+        runtime.set(Self::initial_vruntime());
+
+        let task: Box<Task> = Box::new(Task {
+            rsp: bounds
+                .top
+                .checked_sub(5 * size_of::<u64>())
+                .expect("Invalid stack offset for user task")
+                .bits() as u64,
+            stack_bounds: bounds,
+            page_table: SpinLock::new(pgtable),
+            vm_kernel_range,
+            idle_task: false,
+            state: TaskState::RUNNING,
+            id: TASK_ID_ALLOCATOR.next_id(),
+            runtime,
+            nice: 0,
+            profile_ticks: 0,
+            last_schedule_in_tsc: 0,
         });
         Ok(task)
     }
 
+    /// Creates a task and enqueues it onto the least-loaded online CPU's
+    /// run queue, so task creation naturally participates in load
+    /// balancing instead of always landing on the creating CPU.
+    pub fn spawn(entry: extern "C" fn(), flags: u16) -> Result<u32, SvsmError> {
+        let task = Self::create(entry, flags)?;
+        let id = task.id;
+
+        least_loaded_cpu().unwrap_or_else(this_cpu).enqueue_task(task);
+
+        Ok(id)
+    }
+
     pub fn stack_bounds(&self) -> StackBounds {
         self.stack_bounds
     }
 
+    /// Virtual runtime a newly created or woken task should start at: the
+    /// current CPU's `min_vruntime`, minus a small grace period. Seeding
+    /// from `min_vruntime` rather than zero keeps a long-dormant task from
+    /// monopolizing the CPU, and keeps a fresh task from starving the ones
+    /// already runnable.
+    fn initial_vruntime() -> u64 {
+        this_cpu()
+            .min_vruntime()
+            .saturating_sub(VRUNTIME_NEW_TASK_GRACE)
+    }
+
+    /// Adjusts this task's scheduling weight at runtime to match a new
+    /// nice value.
+    pub fn set_nice(&mut self, nice: i8) {
+        self.nice = nice;
+        self.runtime.set_weight(nice_to_weight(nice));
+    }
+
+    /// Re-seeds this task's virtual runtime from the current CPU's
+    /// `min_vruntime`, as done for newly created tasks. Called when a
+    /// sleeping task is requeued as runnable.
+    pub fn wake(&mut self) {
+        self.runtime.set(Self::initial_vruntime());
+    }
+
+    /// Called by the scheduler just before this task's context is
+    /// restored. Wraps [`TaskRuntime::schedule_in`] so the profiler can
+    /// measure the slice that follows.
+    pub fn schedule_in(&mut self) {
+        self.runtime.schedule_in();
+        self.last_schedule_in_tsc = rdtsc();
+    }
+
+    /// Called by the scheduler at the point this task is interrupted and
+    /// deallocated from the CPU. Wraps [`TaskRuntime::schedule_out`] and,
+    /// once `PerCpu::profile_interval` scheduler-out events have elapsed,
+    /// captures a sample of this task into the current CPU's profiling
+    /// ring buffer.
+    pub fn schedule_out(&mut self) {
+        self.runtime.schedule_out();
+        this_cpu().update_min_vruntime(self.runtime.value());
+
+        let runtime_delta = rdtsc() - self.last_schedule_in_tsc;
+        self.profile_ticks += 1;
+        if self.profile_ticks >= this_cpu().profile_interval() {
+            self.profile_ticks = 0;
+            this_cpu().record_profile_sample(self.id, self.sampled_rip(), runtime_delta);
+        }
+    }
+
+    /// Returns whether this task is currently eligible to be selected by
+    /// the scheduler, e.g. `false` while a [`DeadlineRuntime`] task is
+    /// throttled for exhausting its CBS budget ahead of its deadline.
+    pub fn is_eligible(&self, now: u64) -> bool {
+        self.runtime.is_eligible(now)
+    }
+
+    /// Instruction pointer for the profiler: once this task has been
+    /// scheduled out at least once, `self.rsp` points at its saved
+    /// `TaskContext`, whose `ret_addr` field is where execution resumes.
+    fn sampled_rip(&self) -> u64 {
+        unsafe { (*(self.rsp as *const TaskContext)).ret_addr }
+    }
+
     pub fn set_idle_task(&mut self) {
         self.idle_task = true;
     }
@@ -242,10 +580,92 @@ impl Task {
         self.idle_task
     }
 
+    /// Handles a page fault raised while this task was running. Write
+    /// faults on a page [`VMR::fork_cow`] marked copy-on-write are
+    /// serviced here with [`Task::handle_cow_fault`]; everything else is
+    /// the range's own business (growing a lazily-backed mapping, etc.).
     pub fn handle_pf(&self, vaddr: VirtAddr, write: bool) -> Result<(), SvsmError> {
+        if write && self.vm_kernel_range.is_cow(vaddr) {
+            return self.handle_cow_fault(vaddr);
+        }
         self.vm_kernel_range.handle_page_fault(vaddr, write)
     }
 
+    /// Services a write fault on a page [`VMR::fork_cow`] marked read-only
+    /// and copy-on-write: allocates a fresh page, copies the still-shared
+    /// page's contents into it, and remaps the faulting address in this
+    /// task's own page table as private and writable. Only this task's
+    /// page table entry changes; the other fork keeps sharing the
+    /// original page until it, too, takes a write fault on it.
+    fn handle_cow_fault(&self, vaddr: VirtAddr) -> Result<(), SvsmError> {
+        let page_vaddr = page_align(vaddr);
+        let mut pgtable = self.page_table.lock();
+
+        let shared_paddr = pgtable.phys_addr(page_vaddr)?;
+        let shared_vaddr = phys_to_virt(shared_paddr);
+
+        let new_vaddr = allocate_zeroed_page()?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                shared_vaddr.bits() as *const u8,
+                new_vaddr.bits() as *mut u8,
+                PAGE_SIZE,
+            );
+        }
+        let new_paddr = virt_to_phys(new_vaddr);
+
+        pgtable.unmap_4k(page_vaddr)?;
+        pgtable.map_4k(page_vaddr, new_paddr, &PageTable::data_flags())?;
+
+        Ok(())
+    }
+
+    /// Creates a child task that shares this task's code but has its own,
+    /// copy-on-write, private view of this task's virtual memory range.
+    ///
+    /// Rather than eagerly duplicating every mapping, both this task's and
+    /// the child's writable mappings are marked read-only and tagged
+    /// copy-on-write by [`VMR::fork_cow`]. The first task to write to a
+    /// shared page takes the fault in [`Task::handle_pf`], which allocates
+    /// a fresh page, copies the contents across, and restores write
+    /// permission for the faulting task alone.
+    pub fn fork(&self) -> Result<Box<Task>, SvsmError> {
+        let mut pgtable = self.page_table.lock();
+        let mut child_pgtable = pgtable.clone_shared()?;
+        let vm_kernel_range = self.vm_kernel_range.fork_cow(&mut pgtable)?;
+        vm_kernel_range.populate(&mut child_pgtable);
+
+        let mut runtime = TaskRuntimeImpl::default();
+        runtime.set_weight(nice_to_weight(self.nice));
+        runtime.set(Self::initial_vruntime());
+
+        let task: Box<Task> = Box::new(Task {
+            // The stack mapping is duplicated at the same fixed virtual
+            // address in the child's own page table, so the parent's rsp
+            // and stack bounds are valid as-is. Note that `self.rsp` is
+            // the parent's state as of its *last schedule_out*, not the
+            // live registers at this call site: `fork()` is meant to be
+            // called on a suspended task (e.g. a template), the same way
+            // the scheduler itself only ever observes a task's state at
+            // schedule_in/schedule_out boundaries. The child therefore
+            // resumes from that same suspended point, not from wherever
+            // the caller of `fork()` happens to be executing.
+            rsp: self.rsp,
+            stack_bounds: self.stack_bounds,
+            page_table: SpinLock::new(child_pgtable),
+            vm_kernel_range,
+            idle_task: false,
+            state: TaskState::RUNNING,
+            id: TASK_ID_ALLOCATOR.next_id(),
+            runtime,
+            nice: self.nice,
+            profile_ticks: 0,
+            last_schedule_in_tsc: 0,
+        });
+
+        Ok(task)
+    }
+
     fn allocate_stack(
         entry: extern "C" fn(),
     ) -> Result<(Arc<Mapping>, StackBounds, usize), SvsmError> {